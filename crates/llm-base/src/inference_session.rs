@@ -0,0 +1,162 @@
+//! The state carried between calls to [`crate::KnownModel::evaluate`].
+//!
+//! Only the pieces touched by the scratch-buffer sizing change below are
+//! included here; the rest of `InferenceSession` (sampling state, token
+//! history, etc.) lives alongside it in the full workspace.
+
+use crate::Tensor;
+
+/// Scratch-buffer sizing, shared by every model's `evaluate`.
+///
+/// The first `evaluate` call for a session has no way to know how much
+/// memory its graph will need, so it runs a small probe and records the
+/// result here; every later call reuses the estimate (with headroom) to
+/// size the compute buffer instead of relying on a fixed allocation.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MemPerToken {
+    /// Bytes of scratch memory used per token, as measured by the probe run.
+    bytes: Option<usize>,
+}
+impl MemPerToken {
+    /// The headroom multiplier applied on top of the raw `mem_per_token`
+    /// estimate, to absorb ggml's per-object overhead.
+    const HEADROOM: f64 = 1.1;
+
+    /// Record a fresh measurement: `used_bytes` observed while evaluating
+    /// `n_tokens` tokens. A no-op for `n_tokens == 0`: there's nothing to
+    /// learn per-token from an empty batch, and no guarantee `evaluate` is
+    /// never called with one.
+    pub fn record(&mut self, used_bytes: usize, n_tokens: usize) {
+        if n_tokens == 0 {
+            return;
+        }
+        self.bytes = Some(used_bytes / n_tokens);
+    }
+
+    /// Whether a measurement has been recorded yet.
+    pub fn is_known(&self) -> bool {
+        self.bytes.is_some()
+    }
+
+    /// The recommended scratch buffer size for evaluating `n_tokens` tokens,
+    /// or `None` if no measurement has been recorded yet (the caller should
+    /// run a small probe first).
+    pub fn buffer_size_for(&self, n_tokens: usize) -> Option<usize> {
+        self.bytes
+            .map(|per_token| (Self::HEADROOM * (per_token * n_tokens) as f64) as usize)
+    }
+}
+
+pub struct InferenceSession {
+    /// Tokens evaluated so far in this session.
+    pub n_past: usize,
+
+    /// Key/value memory for previously evaluated tokens.
+    pub memory_k: Tensor,
+    pub memory_v: Tensor,
+
+    /// The compute buffer `evaluate` allocates its `ggml::Context` from.
+    /// Grown (never shrunk below what's needed) as `mem_per_token` is
+    /// refined or the prompt grows.
+    pub(crate) ctx0_buf: Vec<u8>,
+
+    /// Per-token memory estimate used to size `ctx0_buf` for calls after
+    /// the first. See [`MemPerToken`].
+    mem_per_token: MemPerToken,
+}
+impl InferenceSession {
+    /// The recommended scratch buffer size for a single token, once known:
+    /// the measured per-token usage with the headroom multiplier already
+    /// applied (see [`MemPerToken::buffer_size_for`]). Exposed so callers can
+    /// preallocate ahead of a known prompt length instead of paying for
+    /// the buffer to grow across the first few calls.
+    pub fn mem_per_token_estimate(&self) -> Option<usize> {
+        self.mem_per_token.buffer_size_for(1)
+    }
+
+    /// Record a probe measurement and resize `ctx0_buf` if the estimate it
+    /// produces is larger than what's currently allocated.
+    pub(crate) fn update_mem_per_token(&mut self, used_bytes: usize, n_tokens: usize) {
+        self.mem_per_token.record(used_bytes, n_tokens);
+        self.ensure_ctx0_buf(n_tokens);
+    }
+
+    /// Grow `ctx0_buf` to fit `n_tokens`, based on the current
+    /// `mem_per_token` estimate. A no-op if the estimate is unknown yet
+    /// (the caller is expected to be running the initial probe) or the
+    /// buffer is already large enough.
+    pub(crate) fn ensure_ctx0_buf(&mut self, n_tokens: usize) {
+        if let Some(needed) = self.mem_per_token.buffer_size_for(n_tokens) {
+            if needed > self.ctx0_buf.len() {
+                self.ctx0_buf.resize(needed, 0);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ggml;
+
+    #[test]
+    fn record_with_zero_tokens_is_a_no_op() {
+        let mut m = MemPerToken::default();
+
+        m.record(0, 0);
+        assert!(!m.is_known());
+
+        m.record(1000, 10);
+        let estimate = m.buffer_size_for(1);
+
+        m.record(999_999, 0);
+        assert_eq!(m.buffer_size_for(1), estimate);
+    }
+
+    #[test]
+    fn buffer_size_for_is_none_until_recorded() {
+        let m = MemPerToken::default();
+        assert!(!m.is_known());
+        assert_eq!(m.buffer_size_for(1), None);
+    }
+
+    #[test]
+    fn buffer_size_for_applies_headroom() {
+        let mut m = MemPerToken::default();
+        m.record(1000, 10); // 100 bytes/token
+
+        assert_eq!(m.buffer_size_for(1), Some(110));
+        assert_eq!(m.buffer_size_for(5), Some(550));
+    }
+
+    fn session_with_buf_len(ctx0_buf_len: usize) -> InferenceSession {
+        let context = ggml::Context::init(1024 * 1024, true);
+        InferenceSession {
+            n_past: 0,
+            memory_k: context.new_f32(0.0),
+            memory_v: context.new_f32(0.0),
+            ctx0_buf: vec![0; ctx0_buf_len],
+            mem_per_token: MemPerToken::default(),
+        }
+    }
+
+    #[test]
+    fn ensure_ctx0_buf_never_shrinks_an_already_larger_buffer() {
+        let mut session = session_with_buf_len(1_000_000);
+        session.mem_per_token.record(100, 1); // needs only 110 bytes
+
+        session.ensure_ctx0_buf(1);
+
+        assert_eq!(session.ctx0_buf.len(), 1_000_000);
+    }
+
+    #[test]
+    fn ensure_ctx0_buf_grows_when_the_estimate_exceeds_the_buffer() {
+        let mut session = session_with_buf_len(10);
+        session.mem_per_token.record(1000, 1); // needs 1100 bytes
+
+        session.ensure_ctx0_buf(1);
+
+        assert_eq!(session.ctx0_buf.len(), 1100);
+    }
+}
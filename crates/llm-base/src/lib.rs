@@ -0,0 +1,25 @@
+//! Shared types used by the model crates in this workspace.
+//!
+//! This crate normally hosts the full loading/inference machinery that
+//! `llm-gptj` and friends build on (tensor loading, inference sessions, the
+//! `ggml` bindings, and so on). Only the pieces touched by this change are
+//! included here; the rest of `llm-base` lives alongside it in the full
+//! workspace.
+
+mod inference_session;
+pub mod model;
+mod model_parameters;
+mod vocabulary;
+
+pub use inference_session::{InferenceSession, MemPerToken};
+pub use model_parameters::{InferenceParameters, InferenceWithPromptParameters, ModelParameters};
+pub use vocabulary::{Vocabulary, VocabularyKind};
+
+/// The `ggml` bindings crate. Depended on here, and re-exported so model
+/// crates only need to depend on `llm-base`.
+pub use ggml;
+/// A `ggml` tensor handle, re-exported from the `ggml` crate.
+pub use ggml::Tensor;
+
+/// The identifier of a token in a [`Vocabulary`].
+pub type TokenId = i32;
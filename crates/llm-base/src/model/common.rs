@@ -0,0 +1,75 @@
+//! Helpers shared by every `KnownModel::evaluate` implementation.
+
+use crate::{ggml, InferenceSession, TokenId};
+
+/// A starting guess for the scratch buffer size, used only until the first
+/// `evaluate` call has measured a real `mem_per_token` estimate.
+const INITIAL_CTX0_BUF_SIZE: usize = 10 * 1024 * 1024;
+
+/// Set up the `ggml::Context` and input embedding tensor that an
+/// `evaluate` call builds its graph in.
+///
+/// The context is backed by `session`'s scratch buffer, which is sized from
+/// the per-token memory estimate recorded by the previous call (see
+/// [`InferenceSession::mem_per_token_estimate`]); the first call for a
+/// session has no estimate yet, so it falls back to a fixed starting size;
+/// pair this with [`ensure_mem_per_token`] to get a real estimate from a
+/// one-token probe before that first call, and with [`update_session`] to
+/// record one from the call itself afterwards.
+pub fn prepare_for_evaluate(
+    _n_layer: usize,
+    session: &mut InferenceSession,
+    input_tokens: &[TokenId],
+) -> (ggml::Context, ggml::Tensor) {
+    let n = input_tokens.len();
+
+    if session.ctx0_buf.is_empty() {
+        session.ctx0_buf.resize(INITIAL_CTX0_BUF_SIZE, 0);
+    }
+    session.ensure_ctx0_buf(n);
+
+    let ctx0 = ggml::Context::init_buffer(&mut session.ctx0_buf);
+    let embd = ctx0.new_tensor_1d(ggml::Type::I32, n);
+    embd.write_i32s(input_tokens);
+
+    (ctx0, embd)
+}
+
+/// If `session` has no `mem_per_token` estimate yet, run a real one-token
+/// probe through `run` before the caller does its real (possibly much
+/// larger) computation, so a long initial prompt is still sized from a
+/// measured estimate instead of the fixed starting buffer.
+///
+/// Only triggers for the first call of a session: `run` is handed exactly
+/// one token, so its own `ensure_mem_per_token` call is a no-op and it
+/// can't recurse further. The probe is built and computed exactly like a
+/// real call (writing into `session`'s key/value memory at its current
+/// `n_past`), but since this only runs when nothing has been evaluated
+/// yet, that memory is immediately overwritten by the real call that
+/// follows -- so `n_past` is restored to its pre-probe value afterwards
+/// rather than left advanced by the probe's single token.
+pub fn ensure_mem_per_token(
+    session: &mut InferenceSession,
+    input_tokens: &[TokenId],
+    mut run: impl FnMut(&mut InferenceSession, &[TokenId]),
+) {
+    if session.mem_per_token_estimate().is_none() && input_tokens.len() > 1 {
+        let n_past_before_probe = session.n_past;
+        run(session, &input_tokens[..1]);
+        session.n_past = n_past_before_probe;
+    }
+}
+
+/// Advance `session.n_past` by the tokens just evaluated, and record how
+/// much of `ctx0`'s scratch buffer this call used, so the next call can
+/// size its buffer from a real `mem_per_token` estimate instead of the
+/// fixed starting size.
+pub fn update_session(
+    session: &mut InferenceSession,
+    ctx0: &ggml::Context,
+    n_input_tokens: usize,
+    n: usize,
+) {
+    session.n_past += n_input_tokens;
+    session.update_mem_per_token(ctx0.used_mem(), n);
+}
@@ -0,0 +1,3 @@
+//! Helpers shared by every `KnownModel::evaluate` implementation.
+
+pub mod common;
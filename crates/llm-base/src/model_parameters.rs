@@ -0,0 +1,39 @@
+//! Parameters that control how a model is loaded and run, independent of
+//! the hyperparameters that come from the model file itself.
+
+/// Parameters for instantiating a [`crate::KnownModel`].
+#[derive(Debug, Clone)]
+pub struct ModelParameters {
+    /// The number of context tokens to allocate memory for.
+    pub n_context_tokens: usize,
+    /// The number of layers, counted from the end of the model, to offload
+    /// onto an accelerator during `evaluate`. `0` keeps the whole model on
+    /// the CPU, which remains the default and the only supported mode
+    /// without the `metal` feature.
+    pub n_gpu_layers: usize,
+    /// The parameters to use during inference.
+    pub inference_params: InferenceParameters,
+    /// The parameters to use when inferring with a prompt.
+    pub inference_prompt_params: InferenceWithPromptParameters,
+}
+impl Default for ModelParameters {
+    fn default() -> Self {
+        Self {
+            n_context_tokens: 2048,
+            n_gpu_layers: 0,
+            inference_params: Default::default(),
+            inference_prompt_params: Default::default(),
+        }
+    }
+}
+
+/// Parameters for an individual `evaluate` call.
+#[derive(Debug, Default, Clone)]
+pub struct InferenceParameters {
+    /// The number of threads to use for evaluation.
+    pub n_threads: usize,
+}
+
+/// Parameters for inferring with a prompt.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct InferenceWithPromptParameters {}
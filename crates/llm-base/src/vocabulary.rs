@@ -0,0 +1,225 @@
+//! The vocabulary used by a model to map between token ids and their text
+//! representation.
+//!
+//! Most of the models in this workspace (GPT-J, GPT-2-family models) use a
+//! byte-level BPE vocabulary, where every token is simply looked up verbatim.
+//! Replit-style code models instead ship a SentencePiece Unigram vocabulary,
+//! where each piece carries a log-probability `score` and encoding a word
+//! requires finding its maximum-likelihood segmentation into known pieces.
+//! [`Vocabulary`] supports both, selected via [`VocabularyKind`] at load time.
+
+use std::collections::HashMap;
+
+use crate::TokenId;
+
+/// The whitespace meta-symbol (U+2581, "▁") that SentencePiece Unigram
+/// vocabularies use in place of the literal space character.
+const UNIGRAM_WHITESPACE_CHAR: char = '\u{2581}';
+
+/// Which tokenization scheme a [`Vocabulary`] should use.
+#[derive(Debug, Clone, Default)]
+pub enum VocabularyKind {
+    /// Byte-level/GPT-2-style BPE: tokens are looked up directly, with no
+    /// segmentation step required at encode time.
+    #[default]
+    Bpe,
+    /// SentencePiece Unigram: each piece has an associated `score`, and
+    /// encoding runs a Viterbi search for the maximum-scoring segmentation.
+    Unigram {
+        /// The score of each known piece, keyed by its raw bytes.
+        scores: HashMap<Vec<u8>, f32>,
+    },
+}
+
+/// The vocabulary used by a model.
+#[derive(Debug, Clone, Default)]
+pub struct Vocabulary {
+    /// Maps every token to its id.
+    pub token_to_id: HashMap<Vec<u8>, TokenId>,
+    /// Maps every id to its token.
+    pub id_to_token: Vec<Vec<u8>>,
+    /// The longest token in this vocabulary, in bytes.
+    pub max_token_length: usize,
+    /// The tokenization scheme this vocabulary uses to turn text into tokens.
+    pub kind: VocabularyKind,
+}
+impl Vocabulary {
+    /// Add a token to the vocabulary, with an optional Unigram `score`.
+    ///
+    /// `score` must be `Some` if and only if this vocabulary's `kind` is
+    /// [`VocabularyKind::Unigram`].
+    pub fn push_token(&mut self, id: TokenId, token: Vec<u8>, score: Option<f32>) {
+        self.max_token_length = self.max_token_length.max(token.len());
+        self.token_to_id.insert(token.clone(), id);
+
+        if let (VocabularyKind::Unigram { scores }, Some(score)) = (&mut self.kind, score) {
+            scores.insert(token.clone(), score);
+        }
+
+        if self.id_to_token.len() <= id as usize {
+            self.id_to_token.resize(id as usize + 1, Vec::new());
+        }
+        self.id_to_token[id as usize] = token;
+    }
+
+    /// Tokenize `text` according to this vocabulary's [`VocabularyKind`].
+    pub fn tokenize(&self, text: &str) -> Vec<(Vec<u8>, TokenId)> {
+        match &self.kind {
+            VocabularyKind::Bpe => self.tokenize_bpe(text),
+            VocabularyKind::Unigram { scores } => self.tokenize_unigram(text, scores),
+        }
+    }
+
+    fn tokenize_bpe(&self, text: &str) -> Vec<(Vec<u8>, TokenId)> {
+        // Each byte of `text` is looked up as a single-byte token; bytes with
+        // no such token are dropped, and multi-byte tokens are never emitted.
+        text.as_bytes()
+            .iter()
+            .filter_map(|&b| {
+                let token = vec![b];
+                self.token_to_id
+                    .get(&token)
+                    .map(|&id| (token.clone(), id))
+            })
+            .collect()
+    }
+
+    /// Encode `text` by replacing spaces with the Unigram whitespace
+    /// meta-symbol, then running the Viterbi segmentation word-by-word.
+    ///
+    /// SentencePiece Unigram vocabularies key word-initial pieces with the
+    /// meta-symbol as a *prefix* (e.g. `"▁world"`, never `"world▁"`), so a
+    /// leading marker is prepended to the whole input before splitting, and
+    /// re-attached to the front of each word rather than left trailing the
+    /// one before it.
+    fn tokenize_unigram(
+        &self,
+        text: &str,
+        scores: &HashMap<Vec<u8>, f32>,
+    ) -> Vec<(Vec<u8>, TokenId)> {
+        let marker = UNIGRAM_WHITESPACE_CHAR.to_string();
+        let normalized = format!("{marker}{text}").replace(' ', &marker);
+
+        let mut output = Vec::new();
+        for word in normalized.split(UNIGRAM_WHITESPACE_CHAR) {
+            if word.is_empty() {
+                continue;
+            }
+            let prefixed = format!("{marker}{word}");
+            output.extend(self.viterbi_segment(prefixed.as_bytes(), scores));
+        }
+        output
+    }
+
+    /// Find the maximum-likelihood segmentation of `word` into known pieces.
+    ///
+    /// `best_score[end]` holds the highest total score of any segmentation
+    /// of `word[0..end]`, and `best_start[end]` remembers where the last
+    /// piece in that segmentation began, so the token sequence can be
+    /// recovered by backtracking from `len`.
+    fn viterbi_segment(
+        &self,
+        word: &[u8],
+        scores: &HashMap<Vec<u8>, f32>,
+    ) -> Vec<(Vec<u8>, TokenId)> {
+        let len = word.len();
+        let mut best_score = vec![f32::NEG_INFINITY; len + 1];
+        let mut best_start: Vec<Option<usize>> = vec![None; len + 1];
+        best_score[0] = 0.0;
+
+        for start in 0..len {
+            if !best_score[start].is_finite() {
+                continue;
+            }
+            for end in (start + 1)..=len {
+                let piece = &word[start..end];
+                let Some(&score) = scores.get(piece) else {
+                    continue;
+                };
+                let cand = best_score[start] + score;
+                if cand > best_score[end] {
+                    best_score[end] = cand;
+                    best_start[end] = Some(start);
+                }
+            }
+        }
+
+        let mut spans = Vec::new();
+        let mut end = len;
+        while end > 0 {
+            match best_start[end] {
+                Some(start) => {
+                    spans.push(start..end);
+                    end = start;
+                }
+                None => {
+                    // No segmentation reaches `end` (pieces starting after an
+                    // unreachable position are never tried); fall back to an
+                    // `<unk>` for just this one byte and keep backtracking.
+                    spans.push((end - 1)..end);
+                    end -= 1;
+                }
+            }
+        }
+        spans.reverse();
+
+        spans
+            .into_iter()
+            .map(|span| {
+                let piece = word[span].to_vec();
+                let id = self
+                    .token_to_id
+                    .get(&piece)
+                    .copied()
+                    .or_else(|| self.token_to_id.get("<unk>".as_bytes()).copied())
+                    .expect("vocabulary must contain an <unk> fallback token");
+                (piece, id)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unigram_vocab(pieces: &[(&str, TokenId, f32)]) -> Vocabulary {
+        let mut vocab = Vocabulary {
+            kind: VocabularyKind::Unigram {
+                scores: HashMap::new(),
+            },
+            ..Default::default()
+        };
+        for &(token, id, score) in pieces {
+            vocab.push_token(id, token.as_bytes().to_vec(), Some(score));
+        }
+        vocab
+    }
+
+    #[test]
+    fn unigram_segments_each_word_with_a_leading_marker() {
+        let vocab = unigram_vocab(&[("▁Hello", 0, -1.0), ("▁world", 1, -1.0), ("<unk>", 2, -10.0)]);
+
+        let ids: Vec<TokenId> = vocab
+            .tokenize("Hello world")
+            .into_iter()
+            .map(|(_, id)| id)
+            .collect();
+
+        assert_eq!(ids, vec![0, 1]);
+    }
+
+    #[test]
+    fn unigram_falls_back_to_unk_for_unknown_bytes() {
+        let vocab = unigram_vocab(&[("▁Hi", 0, -1.0), ("<unk>", 1, -10.0)]);
+
+        let ids: Vec<TokenId> = vocab
+            .tokenize("Hi there")
+            .into_iter()
+            .map(|(_, id)| id)
+            .collect();
+
+        assert_eq!(ids[0], 0);
+        assert!(ids[1..].iter().all(|&id| id == 1));
+    }
+}
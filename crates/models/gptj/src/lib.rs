@@ -99,10 +99,40 @@ impl KnownModel for GptJ {
 
         let ModelParameters {
             n_context_tokens,
+            n_gpu_layers,
             inference_params,
             inference_prompt_params,
         } = params;
 
+        // Tag the weights of the last `n_gpu_layers` layers (and the final
+        // norm/head, which every remaining token passes through) for device
+        // residence, so `evaluate` can schedule their ops on the
+        // accelerator instead of the CPU.
+        #[cfg(feature = "metal")]
+        {
+            let n_gpu_layers = n_gpu_layers.min(layers.len());
+            for layer in layers.iter().rev().take(n_gpu_layers) {
+                layer.ln_1_g.transfer_to_device();
+                layer.ln_1_b.transfer_to_device();
+                layer.c_attn_q_proj_w.transfer_to_device();
+                layer.c_attn_k_proj_w.transfer_to_device();
+                layer.c_attn_v_proj_w.transfer_to_device();
+                layer.c_attn_proj_w.transfer_to_device();
+                layer.c_mlp_fc_w.transfer_to_device();
+                layer.c_mlp_fc_b.transfer_to_device();
+                layer.c_mlp_proj_w.transfer_to_device();
+                layer.c_mlp_proj_b.transfer_to_device();
+            }
+            if n_gpu_layers > 0 {
+                ln_f_g.transfer_to_device();
+                ln_f_b.transfer_to_device();
+                lmh_g.transfer_to_device();
+                lmh_b.transfer_to_device();
+            }
+        }
+        #[cfg(not(feature = "metal"))]
+        let _ = n_gpu_layers;
+
         Ok(GptJ {
             hyperparameters,
             n_context_tokens,
@@ -150,6 +180,15 @@ impl KnownModel for GptJ {
         } = self.hyperparameters;
         let n_ctx = self.n_context_tokens;
 
+        common::ensure_mem_per_token(session, input_tokens, |session, probe_tokens| {
+            self.evaluate(
+                session,
+                params,
+                probe_tokens,
+                &mut EvaluateOutputRequest::default(),
+            );
+        });
+
         let (ctx0, embd) = common::prepare_for_evaluate(n_layer, session, input_tokens);
 
         let n_past = session.n_past;
@@ -164,6 +203,11 @@ impl KnownModel for GptJ {
         let memory_v_size = memory_v.element_size();
 
         let mut gf = ggml::ComputationGraph::new(n_threads);
+        // Ops whose inputs were tagged for device residence in `new` are
+        // scheduled on that device instead of the CPU; everything else
+        // (the default with no `n_gpu_layers`) runs exactly as before.
+        #[cfg(feature = "metal")]
+        gf.set_offload_callback(|tensor| tensor.is_on_device());
 
         for il in 0..n_layer {
             // norm
@@ -302,6 +346,11 @@ impl KnownModel for GptJ {
         gf.build_forward_expand(&input_layer);
         ctx0.graph_compute(&mut gf);
 
+        // if the last layers ran on the device, bring the result back to
+        // host memory before reading tokens/logits out of it
+        #[cfg(feature = "metal")]
+        let input_layer = ctx0.op_cpy_to_host(&input_layer);
+
         // finish evaluation
         common::read_last_token(session, &input_layer, n_vocab, n);
         common::extract_logits(output_request, &input_layer, n_vocab, n);
@@ -0,0 +1,529 @@
+// Ref: https://github.com/ggerganov/ggml/blob/abea4b7/examples/gpt-neox/main.cpp
+
+use std::{error::Error, path::Path};
+
+use ggml::Tensor;
+use llm_base::{
+    ggml, model::common, util, BasicWriteError, EvaluateOutputRequest, FileType,
+    InferenceParameters, InferenceSession, InferenceSessionParameters,
+    InferenceWithPromptParameters, KnownModel, LoadError, LoadProgress, Mmap, ModelParameters,
+    TensorLoader, TokenId, Vocabulary,
+};
+
+/// The GPT-NeoX model.
+///
+/// Like GPT-J, it uses RoPE; unlike GPT-J, it fuses Q/K/V into a single
+/// tensor per layer, and its residual wiring is configurable: EleutherAI's 20B checkpoint uses the same
+/// parallel attention/feed-forward residual as GPT-J, while some smaller
+/// checkpoints run the two sub-layers sequentially instead. This is
+/// controlled by the `par_res` hyperparameter.
+pub struct GptNeoX {
+    hyperparameters: Hyperparameters,
+    n_context_tokens: usize,
+
+    vocabulary: Vocabulary,
+
+    // normalization
+    ln_f_g: Tensor,
+    ln_f_b: Tensor,
+
+    // token embedding
+    wte: Tensor,
+
+    // language model head
+    lmh_g: Tensor,
+
+    layers: Vec<Layer>,
+
+    inference_params: InferenceParameters,
+    inference_prompt_params: InferenceWithPromptParameters,
+
+    /// Needs to kept alive while the model is alive
+    _mmap: Option<Mmap>,
+
+    // Must be kept alive for the model
+    _context: ggml::Context,
+}
+
+unsafe impl Send for GptNeoX {}
+unsafe impl Sync for GptNeoX {}
+
+impl GptNeoX {
+    /// Load the model from `path` with `n_context_tokens` context tokens.
+    ///
+    /// The status of the loading process will be reported through `load_progress_callback`.
+    pub fn load(
+        path: &Path,
+        prefer_mmap: bool,
+        params: ModelParameters,
+        load_progress_callback: impl FnMut(LoadProgress),
+    ) -> Result<GptNeoX, LoadError> {
+        llm_base::load(path, prefer_mmap, params, load_progress_callback)
+    }
+}
+
+impl KnownModel for GptNeoX {
+    type Hyperparameters = Hyperparameters;
+
+    fn new<E: Error>(
+        hyperparameters: Self::Hyperparameters,
+        params: ModelParameters,
+        vocabulary: Vocabulary,
+        tensor_loader: impl TensorLoader<E>,
+    ) -> Result<Self, E>
+    where
+        Self: Sized,
+    {
+        let mut tl = tensor_loader;
+
+        // prepare memory for weights
+        let wte = tl.load("gpt_neox.embed_in.weight")?;
+        let ln_f_g = tl.load("gpt_neox.final_layer_norm.weight")?;
+        let ln_f_b = tl.load("gpt_neox.final_layer_norm.bias")?;
+        let lmh_g = tl.load("embed_out.weight")?;
+
+        let mut layers = Vec::new();
+        for i in 0..hyperparameters.n_layer {
+            let layer = Layer {
+                ln_1_g: tl.load(&format!("gpt_neox.layers.{i}.input_layernorm.weight"))?,
+                ln_1_b: tl.load(&format!("gpt_neox.layers.{i}.input_layernorm.bias"))?,
+                ln_2_g: tl.load(&format!(
+                    "gpt_neox.layers.{i}.post_attention_layernorm.weight"
+                ))?,
+                ln_2_b: tl.load(&format!(
+                    "gpt_neox.layers.{i}.post_attention_layernorm.bias"
+                ))?,
+                c_attn_attn_w: tl.load(&format!(
+                    "gpt_neox.layers.{i}.attention.query_key_value.weight"
+                ))?,
+                c_attn_attn_b: tl.load(&format!(
+                    "gpt_neox.layers.{i}.attention.query_key_value.bias"
+                ))?,
+                c_attn_proj_w: tl.load(&format!("gpt_neox.layers.{i}.attention.dense.weight"))?,
+                c_attn_proj_b: tl.load(&format!("gpt_neox.layers.{i}.attention.dense.bias"))?,
+                c_mlp_fc_w: tl.load(&format!("gpt_neox.layers.{i}.mlp.dense_h_to_4h.weight"))?,
+                c_mlp_fc_b: tl.load(&format!("gpt_neox.layers.{i}.mlp.dense_h_to_4h.bias"))?,
+                c_mlp_proj_w: tl.load(&format!("gpt_neox.layers.{i}.mlp.dense_4h_to_h.weight"))?,
+                c_mlp_proj_b: tl.load(&format!("gpt_neox.layers.{i}.mlp.dense_4h_to_h.bias"))?,
+            };
+
+            layers.push(layer);
+        }
+
+        let (_context, _, _mmap) = tl.finish();
+
+        let ModelParameters {
+            n_context_tokens,
+            inference_params,
+            inference_prompt_params,
+            ..
+        } = params;
+
+        Ok(GptNeoX {
+            hyperparameters,
+            n_context_tokens,
+            vocabulary,
+            ln_f_g,
+            ln_f_b,
+            wte,
+            lmh_g,
+            layers,
+            inference_params,
+            inference_prompt_params,
+            _mmap,
+            _context,
+        })
+    }
+
+    fn start_session(&self, params: InferenceSessionParameters) -> InferenceSession {
+        InferenceSession::new(
+            params,
+            self.hyperparameters.n_ctx,
+            self.hyperparameters.n_layer,
+            self.hyperparameters.n_embd,
+            self.hyperparameters.n_vocab,
+        )
+    }
+
+    fn evaluate(
+        &self,
+        session: &mut InferenceSession,
+        params: &InferenceParameters,
+        input_tokens: &[TokenId],
+        output_request: &mut EvaluateOutputRequest,
+    ) {
+        let n = input_tokens.len();
+        let n_threads = params.n_threads;
+
+        let Hyperparameters {
+            n_embd,
+            n_head,
+            n_vocab,
+            n_layer,
+            n_rot,
+            par_res,
+            ..
+        } = self.hyperparameters;
+        let n_ctx = self.n_context_tokens;
+
+        common::ensure_mem_per_token(session, input_tokens, |session, probe_tokens| {
+            self.evaluate(
+                session,
+                params,
+                probe_tokens,
+                &mut EvaluateOutputRequest::default(),
+            );
+        });
+
+        let (ctx0, embd) = common::prepare_for_evaluate(n_layer, session, input_tokens);
+
+        let n_past = session.n_past;
+
+        // wte
+        let mut input_layer = ctx0.op_get_rows(&self.wte, &embd);
+
+        let memory_k = &session.memory_k;
+        let memory_k_size = memory_k.element_size();
+
+        let memory_v = &session.memory_v;
+        let memory_v_size = memory_v.element_size();
+
+        let mut gf = ggml::ComputationGraph::new(n_threads);
+
+        for il in 0..n_layer {
+            // input_layernorm, feeds the attention and (when par_res) the
+            // feed-forward block as well
+            let input_ln = ctx0.op_add(
+                &ctx0.op_mul(
+                    &ctx0.op_repeat(&self.layers[il].ln_1_g, &ctx0.op_norm(&input_layer)),
+                    &ctx0.op_norm(&input_layer),
+                ),
+                &ctx0.op_repeat(&self.layers[il].ln_1_b, &input_layer),
+            );
+
+            // self-attention, fused qkv projection
+            let qkv = ctx0.op_add(
+                &ctx0.op_mul_mat(&self.layers[il].c_attn_attn_w, &input_ln),
+                &ctx0.op_repeat(&self.layers[il].c_attn_attn_b, &input_ln),
+            );
+
+            let qcur = ctx0.op_rope(
+                &ctx0.op_view_3d(
+                    &qkv,
+                    (n_embd / n_head, n_head, n),
+                    (qkv.element_size() * 3 * (n_embd / n_head), qkv.element_size() * 3 * n_embd),
+                    0,
+                ),
+                n_past,
+                n_rot,
+                0,
+            );
+            let kcur = ctx0.op_rope(
+                &ctx0.op_view_3d(
+                    &qkv,
+                    (n_embd / n_head, n_head, n),
+                    (qkv.element_size() * 3 * (n_embd / n_head), qkv.element_size() * 3 * n_embd),
+                    qkv.element_size() * (n_embd / n_head),
+                ),
+                n_past,
+                n_rot,
+                0,
+            );
+            let vcur = ctx0.op_transpose(&ctx0.op_cont(&ctx0.op_view_3d(
+                &qkv,
+                (n_embd / n_head, n_head, n),
+                (qkv.element_size() * 3 * (n_embd / n_head), qkv.element_size() * 3 * n_embd),
+                2 * qkv.element_size() * (n_embd / n_head),
+            )));
+
+            // self-attention store key and value to memory
+            let k = ctx0.op_view_1d(
+                memory_k,
+                n * n_embd,
+                (memory_k_size * n_embd) * (il * n_ctx + n_past),
+            );
+            let v = ctx0.op_view_2d(
+                memory_v,
+                (n, n_embd),
+                n_ctx * memory_v_size,
+                (il * n_ctx) * memory_v_size * n_embd + n_past * memory_v_size,
+            );
+
+            gf.build_forward_expand(&ctx0.op_cpy(&kcur, &k));
+            gf.build_forward_expand(&ctx0.op_cpy(&vcur, &v));
+
+            let q = ctx0.op_permute(&qcur, 0, 2, 1, 3);
+            let big_k = ctx0.op_permute(
+                &ctx0.op_reshape_3d(
+                    &ctx0.op_view_1d(
+                        memory_k,
+                        (n_past + n) * n_embd,
+                        il * n_ctx * memory_k_size * n_embd,
+                    ),
+                    n_embd / n_head,
+                    n_head,
+                    n_past + n,
+                ),
+                0,
+                2,
+                1,
+                3,
+            );
+
+            let kq = ctx0.op_mul_mat(&big_k, &q);
+            let kq_scaled = ctx0.op_scale(
+                &kq,
+                &ctx0.new_f32(1f32 / f32::sqrt(n_embd as f32 / n_head as f32)),
+            );
+
+            let kq_masked = ctx0.op_diag_mask_inf(&kq_scaled, n_past);
+            let kq_softmax = ctx0.op_soft_max(&kq_masked);
+
+            let big_v = ctx0.op_view_3d(
+                memory_v,
+                (n_past + n, n_embd / n_head, n_head),
+                (
+                    n_ctx * memory_v_size,
+                    n_ctx * memory_v_size * n_embd / n_head,
+                ),
+                il * n_ctx * memory_v_size * n_embd,
+            );
+
+            let kqv = ctx0.op_mul_mat(&big_v, &kq_softmax);
+            let kqv_merged = ctx0.op_permute(&kqv, 0, 2, 1, 3);
+
+            let attn_out = ctx0.op_mul_mat(
+                &self.layers[il].c_attn_proj_w,
+                &ctx0.op_cpy(&kqv_merged, &ctx0.new_tensor_2d(ggml::Type::F32, n_embd, n)),
+            );
+            let attn_out = ctx0.op_add(
+                &attn_out,
+                &ctx0.op_repeat(&self.layers[il].c_attn_proj_b, &attn_out),
+            );
+
+            if par_res {
+                // parallel residual: both sub-layers read the same
+                // input_layernorm output and their outputs are summed
+                // into the residual stream together, as in GPT-J.
+                let mut ff_in = ctx0.op_mul_mat(&self.layers[il].c_mlp_fc_w, &input_ln);
+                ff_in = ctx0.op_add(
+                    &ctx0.op_repeat(&self.layers[il].c_mlp_fc_b, &ff_in),
+                    &ff_in,
+                );
+                ff_in = ctx0.op_gelu(&ff_in);
+
+                let mut ff_out = ctx0.op_mul_mat(&self.layers[il].c_mlp_proj_w, &ff_in);
+                ff_out = ctx0.op_add(
+                    &ctx0.op_repeat(&self.layers[il].c_mlp_proj_b, &ff_out),
+                    &ff_out,
+                );
+
+                input_layer = ctx0.op_add(&ctx0.op_add(&input_layer, &attn_out), &ff_out);
+            } else {
+                // sequential residual: the attention output is added to the
+                // residual first, and the feed-forward block reads its own
+                // post_attention_layernorm of that updated residual.
+                input_layer = ctx0.op_add(&input_layer, &attn_out);
+
+                let post_attn_ln = ctx0.op_add(
+                    &ctx0.op_mul(
+                        &ctx0.op_repeat(&self.layers[il].ln_2_g, &ctx0.op_norm(&input_layer)),
+                        &ctx0.op_norm(&input_layer),
+                    ),
+                    &ctx0.op_repeat(&self.layers[il].ln_2_b, &input_layer),
+                );
+
+                let mut ff_out = ctx0.op_mul_mat(&self.layers[il].c_mlp_fc_w, &post_attn_ln);
+                ff_out = ctx0.op_add(
+                    &ctx0.op_repeat(&self.layers[il].c_mlp_fc_b, &ff_out),
+                    &ff_out,
+                );
+                ff_out = ctx0.op_gelu(&ff_out);
+                ff_out = ctx0.op_mul_mat(&self.layers[il].c_mlp_proj_w, &ff_out);
+                ff_out = ctx0.op_add(
+                    &ctx0.op_repeat(&self.layers[il].c_mlp_proj_b, &ff_out),
+                    &ff_out,
+                );
+
+                input_layer = ctx0.op_add(&input_layer, &ff_out);
+            }
+        }
+
+        // norm
+        input_layer = ctx0.op_norm(&input_layer);
+        input_layer = ctx0.op_add(
+            &ctx0.op_mul(&ctx0.op_repeat(&self.ln_f_g, &input_layer), &input_layer),
+            &ctx0.op_repeat(&self.ln_f_b, &input_layer),
+        );
+
+        // lm_head
+        input_layer = ctx0.op_mul_mat(&self.lmh_g, &input_layer);
+
+        // run the computation
+        gf.build_forward_expand(&input_layer);
+        ctx0.graph_compute(&mut gf);
+
+        // finish evaluation
+        common::read_last_token(session, &input_layer, n_vocab, n);
+        common::extract_logits(output_request, &input_layer, n_vocab, n);
+        common::extract_embeddings(output_request, &embd, n_embd, n);
+        common::update_session(session, &ctx0, input_tokens.len(), n);
+    }
+
+    fn vocabulary(&self) -> &Vocabulary {
+        &self.vocabulary
+    }
+
+    fn n_context_tokens(&self) -> usize {
+        self.hyperparameters.n_ctx
+    }
+
+    fn eot_token_id(&self) -> TokenId {
+        self.vocabulary
+            .token_to_id
+            .get("<|endoftext|>".as_bytes())
+            .copied()
+            .unwrap()
+    }
+
+    fn inference_params(&self) -> InferenceParameters {
+        self.inference_params.clone()
+    }
+
+    fn inference_prompt_params(&self) -> InferenceWithPromptParameters {
+        self.inference_prompt_params
+    }
+}
+
+/// The hyperparameters of the model.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct Hyperparameters {
+    /// n_vocab
+    pub n_vocab: usize,
+    /// n_ctx
+    pub n_ctx: usize,
+    /// n_embd
+    pub n_embd: usize,
+    /// n_head
+    pub n_head: usize,
+    /// n_layer
+    pub n_layer: usize,
+    /// n_rot
+    pub n_rot: usize,
+    /// Whether the attention and feed-forward sub-layers are computed in
+    /// parallel from the same input_layernorm output (as in GPT-J), or
+    /// sequentially with separate layer norms. This is a required field of
+    /// the header for this model (there's no reliable way to tell, from a
+    /// flat sequence of header ints with no version tag, whether a later
+    /// field is genuinely absent or whether we're just misreading it) --
+    /// converting an older GPT-NeoX file must stamp it in explicitly.
+    pub par_res: bool,
+    /// file_type
+    pub file_type: FileType,
+}
+impl llm_base::Hyperparameters for Hyperparameters {
+    type WriteError = BasicWriteError;
+
+    fn read(reader: &mut dyn std::io::BufRead) -> Result<Self, LoadError> {
+        let hyperparameters = Hyperparameters {
+            n_vocab: util::read_i32(reader)?.try_into()?,
+            n_ctx: util::read_i32(reader)?.try_into()?,
+            n_embd: util::read_i32(reader)?.try_into()?,
+            n_head: util::read_i32(reader)?.try_into()?,
+            n_layer: util::read_i32(reader)?.try_into()?,
+            n_rot: util::read_i32(reader)?.try_into()?,
+            par_res: util::read_i32(reader)? != 0,
+            file_type: {
+                let ftype = util::read_i32(reader)?;
+                FileType::try_from(ftype).map_err(|_| LoadError::UnsupportedFileType(ftype))?
+            },
+        };
+
+        Ok(hyperparameters)
+    }
+
+    fn write(&self, writer: &mut dyn std::io::Write) -> Result<(), Self::WriteError> {
+        util::write_i32(writer, self.n_vocab.try_into()?)?;
+        util::write_i32(writer, self.n_ctx.try_into()?)?;
+        util::write_i32(writer, self.n_embd.try_into()?)?;
+        util::write_i32(writer, self.n_head.try_into()?)?;
+        util::write_i32(writer, self.n_layer.try_into()?)?;
+        util::write_i32(writer, self.n_rot.try_into()?)?;
+        util::write_i32(writer, self.par_res as i32)?;
+        util::write_i32(writer, self.file_type.into())?;
+        Ok(())
+    }
+
+    fn n_vocabulary(&self) -> usize {
+        self.n_vocab
+    }
+}
+
+struct Layer {
+    // normalization
+    ln_1_g: Tensor,
+    ln_1_b: Tensor,
+    ln_2_g: Tensor,
+    ln_2_b: Tensor,
+
+    // attention
+    c_attn_attn_w: Tensor,
+    c_attn_attn_b: Tensor,
+
+    c_attn_proj_w: Tensor,
+    c_attn_proj_b: Tensor,
+
+    // ff
+    c_mlp_fc_w: Tensor,
+    c_mlp_fc_b: Tensor,
+
+    c_mlp_proj_w: Tensor,
+    c_mlp_proj_b: Tensor,
+}
+
+#[cfg(test)]
+impl GptNeoX {
+    /// This does *not* construct a valid model. All of the tensors are entirely
+    /// empty. However, it can be used to determine if some code will compile.
+    fn new_empty() -> Self {
+        let context = ggml::Context::init(1024 * 1024, true);
+
+        Self {
+            hyperparameters: Default::default(),
+            n_context_tokens: 0,
+            vocabulary: Default::default(),
+            ln_f_g: context.new_f32(0.0),
+            ln_f_b: context.new_f32(0.0),
+            wte: context.new_f32(0.0),
+            lmh_g: context.new_f32(0.0),
+            layers: Default::default(),
+            inference_params: Default::default(),
+            inference_prompt_params: Default::default(),
+            _mmap: Default::default(),
+            _context: context,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn can_share_model_between_threads() {
+        let model = Arc::new(GptNeoX::new_empty());
+
+        for _ in 0..4 {
+            let model = model.clone();
+            std::thread::spawn(move || {
+                let _session = model.start_session(Default::default());
+            });
+        }
+
+        let session = model.start_session(Default::default());
+        std::thread::spawn(move || {
+            let _session = session;
+        });
+    }
+}
@@ -0,0 +1,464 @@
+// Ref: https://github.com/ggerganov/ggml/blob/abea4b7/examples/mpt/main.cpp
+
+use std::{error::Error, path::Path};
+
+use ggml::Tensor;
+use llm_base::{
+    ggml, model::common, util, BasicWriteError, EvaluateOutputRequest, FileType,
+    InferenceParameters, InferenceSession, InferenceSessionParameters,
+    InferenceWithPromptParameters, KnownModel, LoadError, LoadProgress, Mmap, ModelParameters,
+    TensorLoader, TokenId, Vocabulary,
+};
+
+/// The MosaicML MPT model (e.g. MPT-7B, MPT-30B).
+///
+/// Unlike GPT-J, MPT has no learned or rotary position embeddings; positional
+/// information is instead injected directly into the attention scores via
+/// ALiBi (Attention with Linear Biases). It also ties its output projection
+/// to the token embedding and fuses Q/K/V into a single tensor per layer.
+pub struct Mpt {
+    hyperparameters: Hyperparameters,
+    n_context_tokens: usize,
+
+    vocabulary: Vocabulary,
+
+    // normalization
+    norm_f_g: Tensor,
+
+    // token embedding, also used as the (tied) language model head
+    wte: Tensor,
+
+    layers: Vec<Layer>,
+
+    inference_params: InferenceParameters,
+    inference_prompt_params: InferenceWithPromptParameters,
+
+    /// Needs to kept alive while the model is alive
+    _mmap: Option<Mmap>,
+
+    // Must be kept alive for the model
+    _context: ggml::Context,
+}
+
+unsafe impl Send for Mpt {}
+unsafe impl Sync for Mpt {}
+
+impl Mpt {
+    /// Load the model from `path` with `n_context_tokens` context tokens.
+    ///
+    /// The status of the loading process will be reported through `load_progress_callback`.
+    pub fn load(
+        path: &Path,
+        prefer_mmap: bool,
+        params: ModelParameters,
+        load_progress_callback: impl FnMut(LoadProgress),
+    ) -> Result<Mpt, LoadError> {
+        llm_base::load(path, prefer_mmap, params, load_progress_callback)
+    }
+}
+
+impl KnownModel for Mpt {
+    type Hyperparameters = Hyperparameters;
+
+    fn new<E: Error>(
+        hyperparameters: Self::Hyperparameters,
+        params: ModelParameters,
+        vocabulary: Vocabulary,
+        tensor_loader: impl TensorLoader<E>,
+    ) -> Result<Self, E>
+    where
+        Self: Sized,
+    {
+        let mut tl = tensor_loader;
+
+        // prepare memory for weights
+        let wte = tl.load("transformer.wte.weight")?;
+        let norm_f_g = tl.load("transformer.norm_f.weight")?;
+
+        let mut layers = Vec::new();
+        for i in 0..hyperparameters.n_layer {
+            let layer = Layer {
+                norm_1_g: tl.load(&format!("transformer.blocks.{i}.norm_1.weight"))?,
+                norm_2_g: tl.load(&format!("transformer.blocks.{i}.norm_2.weight"))?,
+                c_attn_wqkv_w: tl.load(&format!("transformer.blocks.{i}.attn.Wqkv.weight"))?,
+                c_attn_proj_w: tl.load(&format!("transformer.blocks.{i}.attn.out_proj.weight"))?,
+                c_mlp_up_w: tl.load(&format!("transformer.blocks.{i}.ffn.up_proj.weight"))?,
+                c_mlp_down_w: tl.load(&format!("transformer.blocks.{i}.ffn.down_proj.weight"))?,
+            };
+
+            layers.push(layer);
+        }
+
+        let (_context, _, _mmap) = tl.finish();
+
+        let ModelParameters {
+            n_context_tokens,
+            inference_params,
+            inference_prompt_params,
+            ..
+        } = params;
+
+        Ok(Mpt {
+            hyperparameters,
+            n_context_tokens,
+            vocabulary,
+            norm_f_g,
+            wte,
+            layers,
+            inference_params,
+            inference_prompt_params,
+            _mmap,
+            _context,
+        })
+    }
+
+    fn start_session(&self, params: InferenceSessionParameters) -> InferenceSession {
+        InferenceSession::new(
+            params,
+            self.hyperparameters.n_ctx,
+            self.hyperparameters.n_layer,
+            self.hyperparameters.n_embd,
+            self.hyperparameters.n_vocab,
+        )
+    }
+
+    fn evaluate(
+        &self,
+        session: &mut InferenceSession,
+        params: &InferenceParameters,
+        input_tokens: &[TokenId],
+        output_request: &mut EvaluateOutputRequest,
+    ) {
+        let n = input_tokens.len();
+        let n_threads = params.n_threads;
+
+        let Hyperparameters {
+            n_embd,
+            n_head,
+            n_vocab,
+            n_layer,
+            alibi_bias_max,
+            clip_qkv,
+            ..
+        } = self.hyperparameters;
+        let n_ctx = self.n_context_tokens;
+
+        common::ensure_mem_per_token(session, input_tokens, |session, probe_tokens| {
+            self.evaluate(
+                session,
+                params,
+                probe_tokens,
+                &mut EvaluateOutputRequest::default(),
+            );
+        });
+
+        let (ctx0, embd) = common::prepare_for_evaluate(n_layer, session, input_tokens);
+
+        let n_past = session.n_past;
+
+        // wte
+        let mut input_layer = ctx0.op_get_rows(&self.wte, &embd);
+
+        let memory_k = &session.memory_k;
+        let memory_k_size = memory_k.element_size();
+
+        let memory_v = &session.memory_v;
+        let memory_v_size = memory_v.element_size();
+
+        let mut gf = ggml::ComputationGraph::new(n_threads);
+
+        for il in 0..n_layer {
+            // norm
+            let current = ctx0.op_mul(
+                &ctx0.op_repeat(&self.layers[il].norm_1_g, &ctx0.op_norm(&input_layer)),
+                &ctx0.op_norm(&input_layer),
+            );
+
+            // self-attention, fused qkv projection
+            let mut qkv = ctx0.op_mul_mat(&self.layers[il].c_attn_wqkv_w, &current);
+            if clip_qkv > 0.0 {
+                qkv = ctx0.op_clamp(&qkv, -clip_qkv, clip_qkv);
+            }
+
+            let qkv_row_stride = qkv.get_ne()[0] as usize * qkv.element_size();
+            let qcur = ctx0.op_view_2d(&qkv, (n_embd, n), qkv_row_stride, 0);
+            let kcur = ctx0.op_view_2d(
+                &qkv,
+                (n_embd, n),
+                qkv_row_stride,
+                n_embd * qkv.element_size(),
+            );
+            let vcur = ctx0.op_view_2d(
+                &qkv,
+                (n_embd, n),
+                qkv_row_stride,
+                2 * n_embd * qkv.element_size(),
+            );
+
+            // self-attention store key and value to memory
+            let k = ctx0.op_view_1d(
+                memory_k,
+                n * n_embd,
+                (memory_k_size * n_embd) * (il * n_ctx + n_past),
+            );
+            let v = ctx0.op_view_2d(
+                memory_v,
+                (n, n_embd),
+                n_ctx * memory_v_size,
+                (il * n_ctx) * memory_v_size * n_embd + n_past * memory_v_size,
+            );
+
+            gf.build_forward_expand(&ctx0.op_cpy(&kcur, &k));
+            gf.build_forward_expand(&ctx0.op_cpy(&ctx0.op_transpose(&vcur), &v));
+
+            let q = ctx0.op_permute(
+                &ctx0.op_reshape_3d(&qcur, n_embd / n_head, n_head, n),
+                0,
+                2,
+                1,
+                3,
+            );
+            let big_k = ctx0.op_permute(
+                &ctx0.op_reshape_3d(
+                    &ctx0.op_view_1d(
+                        memory_k,
+                        (n_past + n) * n_embd,
+                        il * n_ctx * memory_k_size * n_embd,
+                    ),
+                    n_embd / n_head,
+                    n_head,
+                    n_past + n,
+                ),
+                0,
+                2,
+                1,
+                3,
+            );
+
+            let kq = ctx0.op_mul_mat(&big_k, &q);
+            let kq_scaled = ctx0.op_scale(
+                &kq,
+                &ctx0.new_f32(1f32 / f32::sqrt(n_embd as f32 / n_head as f32)),
+            );
+
+            // ALiBi: bias the scaled scores with a per-head linear penalty on
+            // the distance between query and key positions, in place of the
+            // RoPE/learned position embeddings GPT-J and GPT-2 use.
+            let kq_alibi = ctx0.op_alibi(&kq_scaled, n_past, n_head, alibi_bias_max);
+
+            let kq_masked = ctx0.op_diag_mask_inf(&kq_alibi, n_past);
+            let kq_softmax = ctx0.op_soft_max(&kq_masked);
+
+            let big_v = ctx0.op_view_3d(
+                memory_v,
+                (n_past + n, n_embd / n_head, n_head),
+                (
+                    n_ctx * memory_v_size,
+                    n_ctx * memory_v_size * n_embd / n_head,
+                ),
+                il * n_ctx * memory_v_size * n_embd,
+            );
+
+            let kqv = ctx0.op_mul_mat(&big_v, &kq_softmax);
+            let kqv_merged = ctx0.op_permute(&kqv, 0, 2, 1, 3);
+
+            let mut current =
+                ctx0.op_cpy(&kqv_merged, &ctx0.new_tensor_2d(ggml::Type::F32, n_embd, n));
+
+            // self-attention projection
+            current = ctx0.op_mul_mat(&self.layers[il].c_attn_proj_w, &current);
+
+            // input for the feed-forward block
+            input_layer = ctx0.op_add(&input_layer, &current);
+
+            // feed-forward
+            let ff_in = ctx0.op_mul(
+                &ctx0.op_repeat(&self.layers[il].norm_2_g, &ctx0.op_norm(&input_layer)),
+                &ctx0.op_norm(&input_layer),
+            );
+
+            let mut current = ctx0.op_mul_mat(&self.layers[il].c_mlp_up_w, &ff_in);
+            current = ctx0.op_gelu(&current);
+            current = ctx0.op_mul_mat(&self.layers[il].c_mlp_down_w, &current);
+
+            // input for next layer
+            input_layer = ctx0.op_add(&input_layer, &current);
+        }
+
+        // norm
+        input_layer = ctx0.op_mul(
+            &ctx0.op_repeat(&self.norm_f_g, &ctx0.op_norm(&input_layer)),
+            &ctx0.op_norm(&input_layer),
+        );
+
+        // lm_head, tied to the token embedding
+        input_layer = ctx0.op_mul_mat(&self.wte, &input_layer);
+
+        // run the computation
+        gf.build_forward_expand(&input_layer);
+        ctx0.graph_compute(&mut gf);
+
+        // finish evaluation
+        common::read_last_token(session, &input_layer, n_vocab, n);
+        common::extract_logits(output_request, &input_layer, n_vocab, n);
+        common::extract_embeddings(output_request, &embd, n_embd, n);
+        common::update_session(session, &ctx0, input_tokens.len(), n);
+    }
+
+    fn vocabulary(&self) -> &Vocabulary {
+        &self.vocabulary
+    }
+
+    fn n_context_tokens(&self) -> usize {
+        self.hyperparameters.n_ctx
+    }
+
+    fn eot_token_id(&self) -> TokenId {
+        self.vocabulary
+            .token_to_id
+            .get("<|endoftext|>".as_bytes())
+            .copied()
+            .unwrap()
+    }
+
+    fn inference_params(&self) -> InferenceParameters {
+        self.inference_params.clone()
+    }
+
+    fn inference_prompt_params(&self) -> InferenceWithPromptParameters {
+        self.inference_prompt_params
+    }
+}
+
+/// The hyperparameters of the model.
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct Hyperparameters {
+    /// n_vocab
+    pub n_vocab: usize,
+    /// n_ctx
+    pub n_ctx: usize,
+    /// n_embd
+    pub n_embd: usize,
+    /// n_head
+    pub n_head: usize,
+    /// n_layer
+    pub n_layer: usize,
+    /// Maximum ALiBi bias, `alibi_bias_max` in the original MPT configs.
+    pub alibi_bias_max: f32,
+    /// Clamp Q/K/V activations to `[-clip_qkv, clip_qkv]` when nonzero.
+    pub clip_qkv: f32,
+    /// file_type
+    pub file_type: FileType,
+}
+impl llm_base::Hyperparameters for Hyperparameters {
+    type WriteError = BasicWriteError;
+
+    fn read(reader: &mut dyn std::io::BufRead) -> Result<Self, LoadError> {
+        let hyperparameters = Hyperparameters {
+            n_vocab: util::read_i32(reader)?.try_into()?,
+            n_ctx: util::read_i32(reader)?.try_into()?,
+            n_embd: util::read_i32(reader)?.try_into()?,
+            n_head: util::read_i32(reader)?.try_into()?,
+            n_layer: util::read_i32(reader)?.try_into()?,
+            alibi_bias_max: util::read_f32(reader)?,
+            clip_qkv: util::read_f32(reader)?,
+            file_type: {
+                let ftype = util::read_i32(reader)?;
+                FileType::try_from(ftype).map_err(|_| LoadError::UnsupportedFileType(ftype))?
+            },
+        };
+
+        Ok(hyperparameters)
+    }
+
+    fn write(&self, writer: &mut dyn std::io::Write) -> Result<(), Self::WriteError> {
+        util::write_i32(writer, self.n_vocab.try_into()?)?;
+        util::write_i32(writer, self.n_ctx.try_into()?)?;
+        util::write_i32(writer, self.n_embd.try_into()?)?;
+        util::write_i32(writer, self.n_head.try_into()?)?;
+        util::write_i32(writer, self.n_layer.try_into()?)?;
+        util::write_f32(writer, self.alibi_bias_max)?;
+        util::write_f32(writer, self.clip_qkv)?;
+        util::write_i32(writer, self.file_type.into())?;
+        Ok(())
+    }
+
+    fn n_vocabulary(&self) -> usize {
+        self.n_vocab
+    }
+}
+
+struct Layer {
+    // normalization
+    norm_1_g: Tensor,
+    norm_2_g: Tensor,
+
+    // attention
+    c_attn_wqkv_w: Tensor,
+    c_attn_proj_w: Tensor,
+
+    // ff
+    c_mlp_up_w: Tensor,
+    c_mlp_down_w: Tensor,
+}
+
+#[cfg(test)]
+impl Mpt {
+    /// This does *not* construct a valid model. All of the tensors are entirely
+    /// empty. However, it can be used to determine if some code will compile.
+    fn new_empty() -> Self {
+        let context = ggml::Context::init(1024 * 1024, true);
+
+        Self {
+            hyperparameters: Default::default(),
+            n_context_tokens: 0,
+            vocabulary: Default::default(),
+            norm_f_g: context.new_f32(0.0),
+            wte: context.new_f32(0.0),
+            layers: Default::default(),
+            inference_params: Default::default(),
+            inference_prompt_params: Default::default(),
+            _mmap: Default::default(),
+            _context: context,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn can_share_model_between_threads() {
+        let model = Arc::new(Mpt::new_empty());
+
+        for _ in 0..4 {
+            let model = model.clone();
+            std::thread::spawn(move || {
+                let _session = model.start_session(Default::default());
+            });
+        }
+
+        let session = model.start_session(Default::default());
+        std::thread::spawn(move || {
+            let _session = session;
+        });
+    }
+
+    #[test]
+    fn qkv_row_stride_is_in_bytes_not_elements() {
+        // Regression test for the fused Q/K/V view using `ggml_nelements`
+        // (the row's element count) as a byte stride: for `f32` that packs
+        // rows ~4x too close together. `op_view_2d`'s `nb1` must always be
+        // in bytes, as every offset alongside it already is.
+        let n_embd = 4;
+        let ctx = ggml::Context::init(1024 * 1024, true);
+        let qkv = ctx.new_tensor_2d(ggml::Type::F32, n_embd * 3, 2);
+
+        let row_stride = qkv.get_ne()[0] as usize * qkv.element_size();
+
+        assert_eq!(row_stride, (n_embd * 3) * qkv.element_size());
+        assert_ne!(row_stride, qkv.get_ne()[0] as usize);
+    }
+}